@@ -0,0 +1,132 @@
+use std::{
+    io,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use zstd::stream::raw::{CParameter, Encoder as RawZstdEncoder, Operation};
+
+use crate::zlib_sys::Compressor;
+
+/// Transport compression selected by a client's IDENTIFY `d.compress`
+/// (`"zlib-stream"` / `"zstd-stream"`) or a `?compress=` query param.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Compression {
+    None,
+    Zlib,
+    Zstd,
+}
+
+impl Compression {
+    /// Parse a gateway transport compression value, e.g. from IDENTIFY's
+    /// `d.compress` or a `?compress=` query param.
+    pub fn from_value(value: &str) -> Option<Self> {
+        match value {
+            "zlib-stream" => Some(Self::Zlib),
+            "zstd-stream" => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            Self::None => 0,
+            Self::Zlib => 1,
+            Self::Zstd => 2,
+        }
+    }
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Self::Zlib,
+            2 => Self::Zstd,
+            _ => Self::None,
+        }
+    }
+}
+
+/// Shared, lock-free holder for a connection's current `Compression`,
+/// updated from IDENTIFY and read from the sink task on every message.
+/// Replaces the old single `AtomicBool` now that there's more than one
+/// compressed transport to pick between.
+#[derive(Default)]
+pub struct CompressionMode(AtomicU8);
+
+impl CompressionMode {
+    pub fn new(initial: Compression) -> Self {
+        Self(AtomicU8::new(initial.as_u8()))
+    }
+
+    pub fn store(&self, value: Compression) {
+        self.0.store(value.as_u8(), Ordering::Relaxed);
+    }
+
+    pub fn load(&self) -> Compression {
+        Compression::from_u8(self.0.load(Ordering::Relaxed))
+    }
+}
+
+/// A stream compressor that keeps its dictionary/context alive across
+/// messages, mirroring how the zlib path already reuses one `Compressor`
+/// for the lifetime of a connection.
+pub trait StreamEncoder: Send {
+    fn encode(&mut self, input: &[u8], output: &mut Vec<u8>) -> io::Result<()>;
+}
+
+impl StreamEncoder for Compressor {
+    fn encode(&mut self, input: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+        self.compress(input, output)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))
+    }
+}
+
+/// Scratch buffer size for draining the zstd encoder. `OutBuffer::around`
+/// always writes starting at position 0 and bounds writes by the given
+/// slice's length, so this has to be a real, pre-sized buffer we drain
+/// from ourselves rather than the caller's (possibly empty) output `Vec`.
+const SCRATCH_SIZE: usize = 64 * 1024;
+
+/// Long-lived zstd streaming encoder context for a single connection. Must
+/// be flushed after every message so the client can decode incrementally,
+/// the same way the zlib path relies on `Z_SYNC_FLUSH`.
+pub struct ZstdEncoder<'a> {
+    encoder: RawZstdEncoder<'a>,
+    scratch: Vec<u8>,
+}
+
+impl ZstdEncoder<'_> {
+    pub fn new(level: i32) -> io::Result<Self> {
+        let mut encoder = RawZstdEncoder::with_dictionary(level, &[])?;
+        encoder.set_parameter(CParameter::ChecksumFlag(false))?;
+        Ok(Self {
+            encoder,
+            scratch: vec![0u8; SCRATCH_SIZE],
+        })
+    }
+}
+
+impl StreamEncoder for ZstdEncoder<'_> {
+    fn encode(&mut self, input: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+        let mut in_buffer = zstd::stream::raw::InBuffer::around(input);
+
+        while in_buffer.pos < in_buffer.src.len() {
+            let mut out_buffer = zstd::stream::raw::OutBuffer::around(&mut self.scratch);
+            self.encoder.run(&mut in_buffer, &mut out_buffer)?;
+            output.extend_from_slice(&self.scratch[..out_buffer.pos()]);
+        }
+
+        // Flush so the peer can decode what we've written so far without
+        // waiting for more data on this stream. Each call drains into the
+        // scratch buffer, which we copy out before reusing it.
+        loop {
+            let mut out_buffer = zstd::stream::raw::OutBuffer::around(&mut self.scratch);
+            let remaining = self.encoder.flush(&mut out_buffer)?;
+            output.extend_from_slice(&self.scratch[..out_buffer.pos()]);
+
+            if remaining == 0 {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}
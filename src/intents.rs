@@ -0,0 +1,68 @@
+//! Gateway intent bit flags and the table mapping dispatch event names to
+//! the intent(s) required to receive them, so `forward_shard` can skip
+//! events a client never asked for.
+
+#![allow(dead_code)]
+
+pub const GUILDS: u64 = 1 << 0;
+pub const GUILD_MEMBERS: u64 = 1 << 1;
+pub const GUILD_BANS: u64 = 1 << 2;
+pub const GUILD_EMOJIS_AND_STICKERS: u64 = 1 << 3;
+pub const GUILD_INTEGRATIONS: u64 = 1 << 4;
+pub const GUILD_WEBHOOKS: u64 = 1 << 5;
+pub const GUILD_INVITES: u64 = 1 << 6;
+pub const GUILD_VOICE_STATES: u64 = 1 << 7;
+pub const GUILD_PRESENCES: u64 = 1 << 8;
+pub const GUILD_MESSAGES: u64 = 1 << 9;
+pub const GUILD_MESSAGE_REACTIONS: u64 = 1 << 10;
+pub const GUILD_MESSAGE_TYPING: u64 = 1 << 11;
+pub const DIRECT_MESSAGES: u64 = 1 << 12;
+pub const DIRECT_MESSAGE_REACTIONS: u64 = 1 << 13;
+pub const DIRECT_MESSAGE_TYPING: u64 = 1 << 14;
+pub const GUILD_SCHEDULED_EVENTS: u64 = 1 << 16;
+pub const AUTO_MODERATION_CONFIGURATION: u64 = 1 << 20;
+pub const AUTO_MODERATION_EXECUTION: u64 = 1 << 21;
+
+/// Maps a dispatch event name (`t`) to the intent bit(s) required to
+/// receive it. Events with no entry here (`READY`, `RESUMED`, the guild
+/// member cache sync events we always relay, ...) are always forwarded.
+fn required_intents(event_type: &str) -> Option<u64> {
+    Some(match event_type {
+        "GUILD_CREATE" | "GUILD_UPDATE" | "GUILD_DELETE" | "GUILD_ROLE_CREATE" | "GUILD_ROLE_UPDATE"
+        | "GUILD_ROLE_DELETE" | "CHANNEL_CREATE" | "CHANNEL_UPDATE" | "CHANNEL_DELETE"
+        | "CHANNEL_PINS_UPDATE" | "THREAD_CREATE" | "THREAD_UPDATE" | "THREAD_DELETE"
+        | "THREAD_LIST_SYNC" | "THREAD_MEMBER_UPDATE" | "THREAD_MEMBERS_UPDATE"
+        | "STAGE_INSTANCE_CREATE" | "STAGE_INSTANCE_UPDATE" | "STAGE_INSTANCE_DELETE" => GUILDS,
+        "GUILD_MEMBER_ADD" | "GUILD_MEMBER_UPDATE" | "GUILD_MEMBER_REMOVE" => GUILD_MEMBERS,
+        "GUILD_BAN_ADD" | "GUILD_BAN_REMOVE" => GUILD_BANS,
+        "GUILD_EMOJIS_UPDATE" | "GUILD_STICKERS_UPDATE" => GUILD_EMOJIS_AND_STICKERS,
+        "GUILD_INTEGRATIONS_UPDATE" | "INTEGRATION_CREATE" | "INTEGRATION_UPDATE"
+        | "INTEGRATION_DELETE" => GUILD_INTEGRATIONS,
+        "WEBHOOKS_UPDATE" => GUILD_WEBHOOKS,
+        "INVITE_CREATE" | "INVITE_DELETE" => GUILD_INVITES,
+        "VOICE_STATE_UPDATE" => GUILD_VOICE_STATES,
+        "PRESENCE_UPDATE" => GUILD_PRESENCES,
+        "MESSAGE_CREATE" | "MESSAGE_UPDATE" | "MESSAGE_DELETE" | "MESSAGE_DELETE_BULK" => {
+            GUILD_MESSAGES | DIRECT_MESSAGES
+        }
+        "MESSAGE_REACTION_ADD" | "MESSAGE_REACTION_REMOVE" | "MESSAGE_REACTION_REMOVE_ALL"
+        | "MESSAGE_REACTION_REMOVE_EMOJI" => GUILD_MESSAGE_REACTIONS | DIRECT_MESSAGE_REACTIONS,
+        "TYPING_START" => GUILD_MESSAGE_TYPING | DIRECT_MESSAGE_TYPING,
+        "GUILD_SCHEDULED_EVENT_CREATE" | "GUILD_SCHEDULED_EVENT_UPDATE"
+        | "GUILD_SCHEDULED_EVENT_DELETE" | "GUILD_SCHEDULED_EVENT_USER_ADD"
+        | "GUILD_SCHEDULED_EVENT_USER_REMOVE" => GUILD_SCHEDULED_EVENTS,
+        "AUTO_MODERATION_RULE_CREATE" | "AUTO_MODERATION_RULE_UPDATE"
+        | "AUTO_MODERATION_RULE_DELETE" => AUTO_MODERATION_CONFIGURATION,
+        "AUTO_MODERATION_ACTION_EXECUTION" => AUTO_MODERATION_EXECUTION,
+        _ => return None,
+    })
+}
+
+/// Whether a client that IDENTIFYed with `intents` should receive a
+/// dispatch named `event_type`.
+pub fn allowed(intents: u64, event_type: &str) -> bool {
+    match required_intents(event_type) {
+        Some(required) => intents & required != 0,
+        None => true,
+    }
+}
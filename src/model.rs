@@ -9,6 +9,9 @@ pub struct Identify {
 #[derive(Deserialize)]
 pub struct IdentifyInfo {
     pub shard: [u64; 2],
+    pub compress: Option<String>,
+    pub intents: u64,
+    pub token: String,
 }
 
 #[derive(Deserialize)]
@@ -16,4 +19,19 @@ pub struct Ready {
     pub d: JsonObject,
 }
 
+#[derive(Deserialize)]
+pub struct Resume {
+    pub d: ResumeInfo,
+}
+
+#[derive(Deserialize)]
+pub struct ResumeInfo {
+    pub session_id: String,
+    pub seq: usize,
+    /// Must match the token the session was created with - without this a
+    /// party that merely observes a `session_id` (it's echoed in plaintext
+    /// in READY) could otherwise hijack someone else's session.
+    pub token: String,
+}
+
 pub type JsonObject = halfbrown::HashMap<String, OwnedValue>;
\ No newline at end of file
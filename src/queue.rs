@@ -0,0 +1,128 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use tokio::sync::{Mutex, Notify};
+
+/// What to do once a client's outgoing queue is full.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OverflowPolicy {
+    /// Close the client's connection rather than let the queue grow.
+    Disconnect,
+    /// Evict the oldest queued message to make room for the new one.
+    DropOldest,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct QueueConfig {
+    pub capacity: usize,
+    pub overflow: OverflowPolicy,
+}
+
+/// A bounded, backpressured queue of outgoing messages for one client.
+///
+/// Modeled on netapp's `BytesBuf` circular buffer: capacity is fixed up
+/// front, and once it's full the configured [`OverflowPolicy`] decides
+/// whether to evict the oldest entry or refuse the push and close the
+/// connection, instead of buffering without limit during an event storm.
+pub struct ClientQueue<T> {
+    inner: Mutex<VecDeque<T>>,
+    notify: Notify,
+    close_notify: Notify,
+    capacity: usize,
+    overflow: OverflowPolicy,
+    closed: AtomicBool,
+}
+
+impl<T> ClientQueue<T> {
+    pub fn new(config: QueueConfig) -> Arc<Self> {
+        Arc::new(Self {
+            inner: Mutex::new(VecDeque::with_capacity(config.capacity)),
+            notify: Notify::new(),
+            close_notify: Notify::new(),
+            capacity: config.capacity,
+            overflow: config.overflow,
+            closed: AtomicBool::new(false),
+        })
+    }
+
+    /// Push a message onto the queue. Returns `false` if the queue was
+    /// closed, or was full under `OverflowPolicy::Disconnect` - either way
+    /// the caller should treat the connection as done.
+    pub async fn push(&self, item: T) -> bool {
+        if self.closed.load(Ordering::Relaxed) {
+            return false;
+        }
+
+        let mut queue = self.inner.lock().await;
+
+        if queue.len() >= self.capacity {
+            match self.overflow {
+                OverflowPolicy::DropOldest => {
+                    queue.pop_front();
+                }
+                OverflowPolicy::Disconnect => {
+                    drop(queue);
+                    self.close();
+                    return false;
+                }
+            }
+        }
+
+        queue.push_back(item);
+        drop(queue);
+        self.notify.notify_one();
+        true
+    }
+
+    /// Mark the queue closed, waking any pending `recv_many` so the sink
+    /// can drain what's left and shut down, and any pending `closed()`
+    /// waiter so the reader side can stop and drop the connection.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.notify.notify_waiters();
+        self.close_notify.notify_waiters();
+    }
+
+    /// Resolves once the queue has been closed - under `OverflowPolicy::
+    /// Disconnect`, that means it overflowed and the caller should stop
+    /// reading from and drop the client's connection.
+    pub async fn closed(&self) {
+        // Capture the notification future before checking the flag, same as
+        // `recv_many` below - `notify_waiters` stores no permit, so checking
+        // `closed` first can race a concurrent `close()` and miss the wakeup
+        // forever.
+        let notified = self.close_notify.notified();
+
+        if self.closed.load(Ordering::Relaxed) {
+            return;
+        }
+
+        notified.await;
+    }
+
+    /// Wait for at least one message, then drain everything currently
+    /// queued in one go so the caller can compress/write it as a batch.
+    /// Returns `None` once the queue is closed and empty.
+    pub async fn recv_many(&self) -> Option<Vec<T>> {
+        loop {
+            let notified = self.notify.notified();
+
+            {
+                let mut queue = self.inner.lock().await;
+                if !queue.is_empty() {
+                    return Some(queue.drain(..).collect());
+                }
+                if self.closed.load(Ordering::Relaxed) {
+                    return None;
+                }
+            }
+
+            notified.await;
+        }
+    }
+}
@@ -8,7 +8,7 @@ use log::{debug, error, info, trace, warn};
 use metrics_exporter_prometheus::PrometheusHandle;
 use tokio::{
     io::{AsyncRead, AsyncWrite},
-    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    sync::mpsc::{unbounded_channel, UnboundedReceiver},
 };
 use tokio_tungstenite::{
     tungstenite::{protocol::Role, Error, Message},
@@ -16,18 +16,17 @@ use tokio_tungstenite::{
 };
 use twilight_gateway::shard::raw_message::Message as TwilightMessage;
 
-use std::{
-    convert::Infallible,
-    net::SocketAddr,
-    pin::Pin,
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
-};
+use std::{convert::Infallible, net::SocketAddr, pin::Pin, sync::Arc, time::Duration};
 
 use crate::{
-    deserializer::GatewayEventDeserializer, model::Identify, state::State, upgrade::server_upgrade,
+    compression::{Compression, CompressionMode, StreamEncoder, ZstdEncoder},
+    deserializer::GatewayEventDeserializer,
+    intents,
+    model::{Identify, Resume},
+    queue::{ClientQueue, QueueConfig},
+    sessions::{SessionOwner, SessionStore},
+    state::State,
+    upgrade::server_upgrade,
     zlib_sys::Compressor,
 };
 
@@ -35,46 +34,91 @@ const HELLO: &str = r#"{"t":null,"s":null,"op":10,"d":{"heartbeat_interval":4125
 const HEARTBEAT_ACK: &str = r#"{"t":null,"s":null,"op":11,"d":null}"#;
 const INVALID_SESSION: &str = r#"{"t":null,"s":null,"op":9,"d":false}"#;
 
+/// How the event-forwarding task should start: a brand new client goes
+/// through the full READY/GUILD_CREATE burst, while a resumed one skips
+/// straight to live forwarding from where it left off.
+enum ShardStart {
+    Fresh {
+        shard_id: u64,
+        session_id: String,
+        intents: u64,
+    },
+    Resumed {
+        shard_id: u64,
+        seq: usize,
+        session_id: String,
+        intents: u64,
+    },
+}
+
 async fn forward_shard(
-    mut shard_id_rx: UnboundedReceiver<u64>,
-    stream_writer: UnboundedSender<Message>,
-    mut shard_send_rx: UnboundedReceiver<TwilightMessage>,
+    mut shard_start_rx: UnboundedReceiver<ShardStart>,
+    stream_writer: Arc<ClientQueue<Message>>,
+    shard_send_rx: Arc<ClientQueue<TwilightMessage>>,
     state: State,
+    sessions: SessionStore,
 ) {
-    // Wait for the client's IDENTIFY to finish and acquire the shard ID
-    let shard_id = shard_id_rx.recv().await.unwrap();
-    // Get a handle to the shard
-    let shard_status = state.shards[shard_id as usize].clone();
+    // Wait for the client's IDENTIFY (or a successful RESUME) to finish and
+    // acquire the shard ID
+    let start = shard_start_rx.recv().await.unwrap();
+    let is_resume = matches!(start, ShardStart::Resumed { .. });
 
     // Fake sequence number for the client. We update packets to overwrite it
-    let mut seq: usize = 0;
+    let (shard_id, mut seq, session_id, intents) = match start {
+        ShardStart::Fresh {
+            shard_id,
+            session_id,
+            intents,
+        } => (shard_id, 0usize, session_id, intents),
+        ShardStart::Resumed {
+            shard_id,
+            seq,
+            session_id,
+            intents,
+        } => (shard_id, seq, session_id, intents),
+    };
+
+    // Get a handle to the shard
+    let shard_status = state.shards[shard_id as usize].clone();
 
     // Subscribe to events for this shard
     let mut event_receiver = shard_status.events.subscribe();
 
-    debug!("Starting to send events to client");
-
-    // If there is no READY received for the shard yet, wait for it
-    if shard_status.ready.get().is_none() {
-        shard_status.ready_set.notified().await;
-    }
+    debug!("Starting to send events to client (session {})", session_id);
 
-    // Get a fake ready payload to send to the client
-    let ready_payload = shard_status
-        .guilds
-        .get_ready_payload(shard_status.ready.get().unwrap().clone(), &mut seq);
+    if !is_resume {
+        // If there is no READY received for the shard yet, wait for it
+        if shard_status.ready.get().is_none() {
+            shard_status.ready_set.notified().await;
+        }
 
-    if let Ok(serialized) = simd_json::to_string(&ready_payload) {
-        debug!("Sending newly created READY");
-        let _res = stream_writer.send(Message::Text(serialized));
-    };
+        // Get a fake ready payload to send to the client
+        let mut ready_payload = shard_status
+            .guilds
+            .get_ready_payload(shard_status.ready.get().unwrap().clone(), &mut seq);
+
+        // Hand the client its session_id so it can RESUME after a reconnect
+        if let Some(obj) = ready_payload
+            .get_mut("d")
+            .and_then(|d| d.as_object_mut())
+        {
+            obj.insert("session_id".into(), session_id.clone().into());
+        }
 
-    // Send GUILD_CREATE/GUILD_DELETEs based on guild availability
-    for payload in shard_status.guilds.get_guild_payloads(&mut seq) {
-        if let Ok(serialized) = simd_json::to_string(&payload) {
-            trace!("Sending newly created GUILD_CREATE/GUILD_DELETE payload");
-            let _res = stream_writer.send(Message::Text(serialized));
+        if let Ok(serialized) = simd_json::to_string(&ready_payload) {
+            debug!("Sending newly created READY");
+            sessions.record(&session_id, seq, serialized.clone()).await;
+            let _res = stream_writer.push(Message::Text(serialized)).await;
         };
+
+        // Send GUILD_CREATE/GUILD_DELETEs based on guild availability
+        for payload in shard_status.guilds.get_guild_payloads(&mut seq) {
+            if let Ok(serialized) = simd_json::to_string(&payload) {
+                trace!("Sending newly created GUILD_CREATE/GUILD_DELETE payload");
+                sessions.record(&session_id, seq, serialized.clone()).await;
+                let _res = stream_writer.push(Message::Text(serialized)).await;
+            };
+        }
     }
 
     loop {
@@ -89,11 +133,25 @@ async fn forward_shard(
                     payload.replace_range(sequence_range, &seq.to_string());
                 }
 
-                let _res = stream_writer.send(Message::Text(payload));
+                // Skip dispatches the client didn't subscribe to via intents
+                let event_type = GatewayEventDeserializer::from_json(&payload)
+                    .and_then(|d| d.event_type().map(str::to_owned));
+
+                if let Some(event_type) = &event_type {
+                    if !intents::allowed(intents, event_type) {
+                        trace!("Skipping {} for client without matching intents", event_type);
+                        continue;
+                    }
+                }
+
+                sessions.record(&session_id, seq, payload.clone()).await;
+                let _res = stream_writer.push(Message::Text(payload)).await;
             },
-            Some(command) = shard_send_rx.recv() => {
+            Some(commands) = shard_send_rx.recv_many() => {
                 // Has to be done here because else shard would be moved
-                let _res = shard_status.shard.send(command).await;
+                for command in commands {
+                    let _res = shard_status.shard.send(command).await;
+                }
             },
         };
     }
@@ -102,51 +160,118 @@ async fn forward_shard(
 pub async fn handle_client<S: 'static + AsyncRead + AsyncWrite + Unpin + Send>(
     stream: S,
     state: State,
-    use_zlib: Arc<AtomicBool>,
+    compression: Arc<CompressionMode>,
+    sessions: SessionStore,
+    session_timeout: Duration,
+    queue_config: QueueConfig,
 ) -> Result<(), Error> {
-    let mut compress = Compressor::new(15);
+    // Both encoders keep their dictionary/context alive for the life of
+    // the connection, same as the old single zlib `Compressor` did, so a
+    // client can flip `compress` mid-connection without losing state.
+    let mut zlib = Compressor::new(15);
+    let mut zstd = ZstdEncoder::new(0).unwrap();
 
     let stream = WebSocketStream::from_raw_socket(stream, Role::Server, None).await;
 
     let (mut sink, mut stream) = stream.split();
 
-    // Write all messages from a queue to the sink
-    let (stream_writer, mut stream_receiver) = unbounded_channel::<Message>();
+    // Write all messages from a bounded queue to the sink. A slow client
+    // can only ever make us buffer `queue_config.capacity` messages before
+    // `overflow` kicks in, instead of growing without limit.
+    let stream_writer = ClientQueue::new(queue_config);
 
-    let use_zlib_clone = use_zlib.clone();
+    let compression_clone = compression.clone();
+    let stream_writer_for_sink = stream_writer.clone();
     let sink_task = tokio::spawn(async move {
-        while let Some(msg) = stream_receiver.recv().await {
-            trace!("Sending {:?}", msg);
-
-            if use_zlib_clone.load(Ordering::Relaxed) {
-                let mut compressed = Vec::with_capacity(msg.len());
-                compress
-                    .compress(&msg.into_data(), &mut compressed)
-                    .unwrap();
-
-                sink.send(Message::Binary(compressed)).await?;
-            } else {
-                sink.send(msg).await?;
+        // Encoder output buffer, reused across messages and batches so it
+        // keeps its grown capacity; each compressed frame is still copied
+        // out of it into its own `Vec` before being fed to the sink.
+        let mut compressed = Vec::new();
+
+        while let Some(batch) = stream_writer_for_sink.recv_many().await {
+            trace!("Flushing {} queued message(s)", batch.len());
+
+            for msg in batch {
+                let encoder: Option<&mut dyn StreamEncoder> = match compression_clone.load() {
+                    Compression::None => None,
+                    Compression::Zlib => Some(&mut zlib),
+                    Compression::Zstd => Some(&mut zstd),
+                };
+
+                if let Some(encoder) = encoder {
+                    compressed.clear();
+                    encoder.encode(&msg.into_data(), &mut compressed).unwrap();
+                    sink.feed(Message::Binary(compressed.clone())).await?;
+                } else {
+                    sink.feed(msg).await?;
+                }
             }
+
+            // One flush for the whole batch instead of one per message.
+            sink.flush().await?;
         }
 
         Ok::<(), Error>(())
     });
 
     // Set up a task that will dump all the messages from the shard to the client
-    let (shard_id_tx, shard_id_rx) = unbounded_channel();
-    let (shard_send_tx, shard_send_rx) = unbounded_channel();
+    let (shard_start_tx, shard_start_rx) = unbounded_channel();
+    let shard_send_tx = ClientQueue::new(queue_config);
 
     let shard_forward_task = tokio::spawn(forward_shard(
-        shard_id_rx,
+        shard_start_rx,
         stream_writer.clone(),
-        shard_send_rx,
+        shard_send_tx.clone(),
         state.clone(),
+        sessions.clone(),
     ));
 
-    let _res = stream_writer.send(Message::Text(HELLO.to_string()));
+    // The session currently associated with this connection, if any, along
+    // with its generation (kept around so we can start its post-disconnect
+    // expiry timer below without racing a concurrent resume) and its owner
+    // handle (so we notice being superseded by a RESUME elsewhere).
+    let mut current_session: Option<(String, u64, Arc<SessionOwner>)> = None;
+
+    let _res = stream_writer.push(Message::Text(HELLO.to_string())).await;
+
+    'read: loop {
+        let msg = tokio::select! {
+            // The outgoing queue overflowed under OverflowPolicy::Disconnect;
+            // stop reading so the connection actually closes instead of
+            // silently dropping every future message.
+            _ = stream_writer.closed() => {
+                warn!("Client's outgoing queue overflowed, disconnecting");
+                break 'read;
+            }
+            // Same, but for the client->shard command queue: a client that
+            // floods us with commands can overflow it under Disconnect too,
+            // and that should actually end the connection rather than just
+            // silently dropping every future command.
+            _ = shard_send_tx.closed() => {
+                warn!("Client's outgoing command queue overflowed, disconnecting");
+                break 'read;
+            }
+            // If our session was resumed on another connection, its owner
+            // generation has moved past ours - stop driving it here instead
+            // of racing the new connection for the same state. Checked via
+            // `wait_superseded`, not a bare `Notify`, so a wakeup missed
+            // between loop iterations still gets caught on the next pass.
+            _ = async {
+                match &current_session {
+                    Some((_, generation, owner)) => owner.wait_superseded(*generation).await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                debug!("Session resumed on another connection, disconnecting");
+                break 'read;
+            }
+            msg = stream.next() => msg,
+        };
+
+        let Some(Ok(msg)) = msg else {
+            break 'read;
+        };
 
-    while let Some(Ok(msg)) = stream.next().await {
         let data = msg.into_data();
         let mut payload = unsafe { String::from_utf8_unchecked(data) };
 
@@ -158,7 +283,7 @@ pub async fn handle_client<S: 'static + AsyncRead + AsyncWrite + Unpin + Send>(
         match deserializer.op() {
             1 => {
                 trace!("Sending heartbeat ACK");
-                let _res = stream_writer.send(Message::Text(HEARTBEAT_ACK.to_string()));
+                let _res = stream_writer.push(Message::Text(HEARTBEAT_ACK.to_string())).await;
             }
             2 => {
                 debug!("Client is identifying");
@@ -185,22 +310,79 @@ pub async fn handle_client<S: 'static + AsyncRead + AsyncWrite + Unpin + Send>(
 
                 trace!("Shard ID is {:?}", shard_id);
 
-                if let Some(compress) = identify.d.compress {
-                    use_zlib.store(compress, Ordering::Relaxed);
+                if let Some(compress) = identify.d.compress.as_deref().and_then(Compression::from_value) {
+                    compression.store(compress);
+                }
+
+                // A re-IDENTIFY on the same connection replaces its session;
+                // drop the old one instead of leaking it in the session table.
+                if let Some((old_session_id, _, _)) = current_session.take() {
+                    sessions.remove(&old_session_id).await;
                 }
 
-                let _res = shard_id_tx.send(shard_id);
+                let (session_id, generation, owner) = sessions
+                    .create(shard_id, identify.d.intents, identify.d.token.clone())
+                    .await;
+                current_session = Some((session_id.clone(), generation, owner));
+
+                let _res = shard_start_tx.send(ShardStart::Fresh {
+                    shard_id,
+                    session_id,
+                    intents: identify.d.intents,
+                });
             }
             6 => {
                 debug!("Client is resuming: {:?}", payload);
-                // TODO: Keep track of session IDs and choose one that we have active
-                // This would be unnecessary if people forked their clients though
-                // For now, send an invalid session so they use identify instead
-                let _res = stream_writer.send(Message::text(INVALID_SESSION.to_string()));
+
+                let resume: Resume = match simd_json::from_str(&mut payload) {
+                    Ok(resume) => resume,
+                    Err(e) => {
+                        warn!("Invalid resume payload: {:?}", e);
+                        continue;
+                    }
+                };
+
+                match sessions
+                    .try_resume(&resume.d.session_id, resume.d.seq, &resume.d.token)
+                    .await
+                {
+                    Some((shard_id, replay, latest_seq, intents, generation, owner)) => {
+                        debug!(
+                            "Resuming session {} from seq {}",
+                            resume.d.session_id, resume.d.seq
+                        );
+
+                        for payload in replay {
+                            let _res = stream_writer.push(Message::Text(payload)).await;
+                        }
+
+                        let resumed =
+                            format!(r#"{{"t":"RESUMED","s":{},"op":0,"d":{{}}}}"#, latest_seq);
+                        let _res = stream_writer.push(Message::Text(resumed)).await;
+
+                        current_session = Some((resume.d.session_id.clone(), generation, owner));
+
+                        let _res = shard_start_tx.send(ShardStart::Resumed {
+                            shard_id,
+                            seq: latest_seq,
+                            session_id: resume.d.session_id,
+                            intents,
+                        });
+                    }
+                    None => {
+                        warn!(
+                            "Session {} could not be resumed, sending INVALID_SESSION",
+                            resume.d.session_id
+                        );
+                        let _res = stream_writer
+                            .push(Message::text(INVALID_SESSION.to_string()))
+                            .await;
+                    }
+                }
             }
             _ => {
                 trace!("Sending {:?} to Discord directly", payload);
-                let _res = shard_send_tx.send(TwilightMessage::Text(payload));
+                let _res = shard_send_tx.push(TwilightMessage::Text(payload)).await;
             }
         }
     }
@@ -208,6 +390,13 @@ pub async fn handle_client<S: 'static + AsyncRead + AsyncWrite + Unpin + Send>(
     sink_task.abort();
     shard_forward_task.abort();
 
+    // Keep the session resumable for a while in case the client reconnects.
+    // `expire_after` no-ops if the session gets resumed (and its generation
+    // bumped) before the timer fires.
+    if let Some((session_id, generation, _)) = current_session {
+        sessions.expire_after(session_id, generation, session_timeout);
+    }
+
     Ok(())
 }
 
@@ -0,0 +1,242 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use rand::{distributions::Alphanumeric, Rng};
+use subtle::ConstantTimeEq;
+use tokio::sync::{Mutex, Notify};
+
+/// Number of previously-sent payloads kept per session so a RESUME can
+/// replay everything the client missed. Chosen generously since each
+/// entry is just a ready-to-send JSON string.
+const REPLAY_BUFFER_CAP: usize = 4096;
+
+/// Tracks which connection currently owns a session, so a connection that
+/// gets superseded by a RESUME elsewhere can notice and stop driving stale
+/// state alongside the new connection.
+///
+/// `generation` is sticky, unlike a bare `Notify`: `notify_waiters` only
+/// wakes tasks that are *currently* polling it and stores no permit for
+/// later, so a connection that misses the wakeup (e.g. mid-await between
+/// `'read` loop iterations in `handle_client`) needs something durable to
+/// catch up on next check - the same reason `ClientQueue::closed` pairs an
+/// `AtomicBool` with its own `Notify`.
+pub struct SessionOwner {
+    generation: AtomicU64,
+    notify: Notify,
+}
+
+impl SessionOwner {
+    fn new() -> Self {
+        Self {
+            generation: AtomicU64::new(0),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Bump the generation and wake anyone waiting, to tell whoever
+    /// previously owned the session that it's been resumed elsewhere.
+    fn supersede(&self) -> u64 {
+        let generation = self.generation.fetch_add(1, Ordering::AcqRel) + 1;
+        self.notify.notify_waiters();
+        generation
+    }
+
+    /// Resolves once this session's generation no longer matches
+    /// `owned_generation` - i.e. it's been resumed on another connection
+    /// since the caller last checked.
+    pub async fn wait_superseded(&self, owned_generation: u64) {
+        loop {
+            // Capture the notification before checking, same reasoning as
+            // `ClientQueue::recv_many`/`closed`: `generation` is checked
+            // again immediately after, so even a wakeup we miss here gets
+            // caught by the check instead of being lost for good.
+            let notified = self.notify.notified();
+
+            if self.generation.load(Ordering::Acquire) != owned_generation {
+                return;
+            }
+
+            notified.await;
+        }
+    }
+}
+
+/// Replay state for a single client connection, keyed by its `session_id`.
+///
+/// `seq` and `buffer` use the *client-rewritten* sequence numbers handed
+/// out by `forward_shard`, not the shard's raw broadcast sequence, since
+/// that's what a RESUME's `seq` field refers to.
+struct Session {
+    shard_id: u64,
+    intents: u64,
+    seq: usize,
+    buffer: VecDeque<(usize, String)>,
+    /// Must match a RESUME's `token` field. Without this, anyone who
+    /// observes a `session_id` (it's echoed in plaintext in READY) could
+    /// otherwise hijack the session it belongs to.
+    token: String,
+    /// Shared, persistent owner handle - never replaced, just bumped, so
+    /// every connection that's ever held this session can tell, using only
+    /// the generation it was handed, whether it's still the current owner.
+    owner: Arc<SessionOwner>,
+}
+
+impl Session {
+    fn new(shard_id: u64, intents: u64, token: String) -> Self {
+        Self {
+            shard_id,
+            intents,
+            seq: 0,
+            buffer: VecDeque::with_capacity(REPLAY_BUFFER_CAP),
+            token,
+            owner: Arc::new(SessionOwner::new()),
+        }
+    }
+
+    fn push(&mut self, seq: usize, payload: String) {
+        if self.buffer.len() == REPLAY_BUFFER_CAP {
+            self.buffer.pop_front();
+        }
+        self.seq = seq;
+        self.buffer.push_back((seq, payload));
+    }
+
+    /// Payloads sent after `seq`, in order, or `None` if `seq` is ahead of
+    /// what we've sent or has already fallen out of the replay buffer.
+    fn replay_from(&self, seq: usize) -> Option<Vec<String>> {
+        if seq > self.seq {
+            return None;
+        }
+
+        if let Some((oldest, _)) = self.buffer.front() {
+            if seq < oldest.saturating_sub(1) {
+                return None;
+            }
+        } else if seq != self.seq {
+            return None;
+        }
+
+        Some(
+            self.buffer
+                .iter()
+                .filter(|(s, _)| *s > seq)
+                .map(|(_, payload)| payload.clone())
+                .collect(),
+        )
+    }
+
+    /// Constant-time comparison against the session's token, so a RESUME
+    /// with a guessed token can't be distinguished from one with a wrong
+    /// token by timing - the whole point of checking it in the first place.
+    fn token_matches(&self, token: &str) -> bool {
+        self.token.as_bytes().ct_eq(token.as_bytes()).into()
+    }
+}
+
+/// Shared table of resumable sessions, keyed by the `session_id` handed
+/// out at IDENTIFY time. Cheaply `Clone`able, like `State`.
+#[derive(Clone, Default)]
+pub struct SessionStore {
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
+}
+
+impl SessionStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a freshly IDENTIFYed client and return its new
+    /// `session_id`, its starting generation (always 0), and the owner
+    /// handle the connection should watch to know if it's been superseded
+    /// by a later RESUME elsewhere.
+    pub async fn create(
+        &self,
+        shard_id: u64,
+        intents: u64,
+        token: String,
+    ) -> (String, u64, Arc<SessionOwner>) {
+        let session_id = generate_session_id();
+        let session = Session::new(shard_id, intents, token);
+        let owner = session.owner.clone();
+        self.sessions.lock().await.insert(session_id.clone(), session);
+        (session_id, 0, owner)
+    }
+
+    /// Record a payload that was just sent to the client under `seq`.
+    pub async fn record(&self, session_id: &str, seq: usize, payload: String) {
+        if let Some(session) = self.sessions.lock().await.get_mut(session_id) {
+            session.push(seq, payload);
+        }
+    }
+
+    /// Attempt to resume `session_id` from `seq` with the `token` it was
+    /// created with. On success, returns the shard it belongs to, the
+    /// payloads to replay, the latest seq, the intents the client
+    /// originally IDENTIFYed with, the session's new generation (for a
+    /// follow-up `expire_after` call), and the owner handle for that new
+    /// generation.
+    ///
+    /// Also wakes up whatever connection previously owned this session, so
+    /// it disconnects instead of continuing to drive the same session's
+    /// state alongside the one resuming it here.
+    pub async fn try_resume(
+        &self,
+        session_id: &str,
+        seq: usize,
+        token: &str,
+    ) -> Option<(u64, Vec<String>, usize, u64, u64, Arc<SessionOwner>)> {
+        let mut sessions = self.sessions.lock().await;
+        let session = sessions.get_mut(session_id)?;
+
+        if !session.token_matches(token) {
+            return None;
+        }
+
+        let replay = session.replay_from(seq)?;
+        let generation = session.owner.supersede();
+
+        Some((
+            session.shard_id,
+            replay,
+            session.seq,
+            session.intents,
+            generation,
+            session.owner.clone(),
+        ))
+    }
+
+    /// Drop a session immediately, e.g. when a client re-IDENTIFYs.
+    pub async fn remove(&self, session_id: &str) {
+        self.sessions.lock().await.remove(session_id);
+    }
+
+    /// Schedule a session for removal after `timeout`, unless it gets
+    /// resumed (bumping its generation) before the timer fires.
+    pub fn expire_after(&self, session_id: String, generation: u64, timeout: Duration) {
+        let store = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(timeout).await;
+
+            let mut sessions = store.sessions.lock().await;
+            if let Some(session) = sessions.get(&session_id) {
+                if session.owner.generation.load(Ordering::Acquire) == generation {
+                    sessions.remove(&session_id);
+                }
+            }
+        });
+    }
+}
+
+fn generate_session_id() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}